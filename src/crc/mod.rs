@@ -23,15 +23,15 @@ impl CRC {
 		}
 	}
 
-	pub fn calculate(&self,data : &Vec<u8>, length : Option<u8>) -> u8 {
+	pub fn calculate(&self,data : &Vec<u8>, length : Option<usize>) -> u8 {
 		let length = match length {
 			Some(length) => length,
-			None => data.len() as u8,
+			None => data.len(),
 		};
 
 		let mut crc : u8 = 0;
 		for i in 0..length {
-			let byte = match data.get(i as usize) {
+			let byte = match data.get(i) {
 				Some(byte) => byte,
 				None => break,
 			};
@@ -0,0 +1,664 @@
+use std::collections::HashMap;
+use std::io::Read;
+
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, Nonce};
+use flate2::read::ZlibDecoder;
+
+use crate::crc::CRC;
+use crate::varint::{VarintOutcome, VarintReader};
+
+const START_BYTE : u8 = 0x7E;
+const STOP_BYTE : u8 = 0x81;
+const MAX_PACKET_SIZE : u8 = 0xFE;
+
+/// Longest run of literal bytes a single COBS chain code can span; see
+/// `decode_data_cobs`.
+const COBS_MAX_BLOCK : u8 = 0xFF;
+
+pub(crate) const FLAG_COMPRESSED : u8 = 0x01;
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)] // name matches the byte being awaited, not a type category
+enum DecodeState {
+	FindStartByte,
+	FindIdByte,
+	FindOverheadByte,
+	FindNonce,
+	FindFlags,
+	FindFragmentIndex,
+	FindFragmentTotal,
+	FindPayloadLength,
+	FindPayload,
+	FindCrc,
+	FindStopByte,
+}
+
+/// Outcome of feeding bytes into a [`PacketDecoder`] via `parse_read_bytes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseState {
+	/// A full frame was decoded, CRC-verified and (if encrypted) authenticated;
+	/// read it with `payload()`/`id_byte()`.
+	DataReady,
+	/// All of the given bytes were consumed, mid-frame.
+	InProgress,
+	/// Called with an empty slice; nothing to do.
+	NoData,
+	CrcError,
+	PayloadError,
+	StopByteError,
+	/// The Poly1305 tag didn't match; the frame was discarded undelivered.
+	AuthError,
+}
+
+struct Reassembly {
+	fragment_total : usize,
+	next_index : usize,
+	buffer : Vec<u8>,
+}
+
+enum ReassembleOutcome {
+	/// The last fragment arrived; the full message is in `self.payload`.
+	Complete,
+	/// Folded in; still waiting on more fragments.
+	Pending,
+	/// Out-of-order fragment or the allocation guard tripped; discarded.
+	Rejected,
+}
+
+/// Standalone COBS/CRC frame decoder. It owns no I/O handle, so it can be
+/// driven from a serial port, an in-memory buffer or a test fixture alike.
+/// It uses `std` (`HashMap`, `Vec`, `std::io::Read`), so it is not usable
+/// in `no_std` builds. `SerialTransfer::available` is just a thin
+/// wrapper that reads bytes off the port and feeds them to one of these.
+pub struct PacketDecoder {
+	crc : CRC,
+	cipher : Option<ChaCha20Poly1305>,
+	large_payload : bool,
+	max_payload_size : usize,
+	compression : bool,
+	state : DecodeState,
+
+	id_byte : u8,
+	overhead_byte : u8,
+	nonce : [u8; 12],
+	nonce_bytes_read : usize,
+	flags : u8,
+	varint : VarintReader,
+	fragment_index : usize,
+	fragment_total : usize,
+	payload_length : usize,
+	payload : Vec<u8>,
+	reassembly : HashMap<u8, Reassembly>,
+
+	/// The (session prefix, highest counter accepted) pair from the last
+	/// authenticated frame, so a captured frame played back later - or a
+	/// duplicate within the same session - is rejected instead of
+	/// re-delivered. `None` until the first encrypted frame arrives.
+	replay_guard : Option<([u8; 8], u32)>,
+}
+
+impl PacketDecoder {
+
+	pub fn new(crc : CRC) -> PacketDecoder {
+		PacketDecoder {
+			crc,
+			cipher : None,
+			large_payload : false,
+			max_payload_size : crate::DEFAULT_MAX_PAYLOAD_SIZE,
+			compression : false,
+			state : DecodeState::FindStartByte,
+			id_byte : 0,
+			overhead_byte : 0,
+			nonce : [0; 12],
+			nonce_bytes_read : 0,
+			flags : 0,
+			varint : VarintReader::default(),
+			fragment_index : 0,
+			fragment_total : 1,
+			payload_length : 0,
+			payload : Vec::new(),
+			reassembly : HashMap::new(),
+			replay_guard : None,
+		}
+	}
+
+	/// Authenticates and decrypts every frame with `cipher`, expecting the
+	/// 12-byte nonce (8-byte random session prefix, 4-byte counter) this
+	/// crate's encoder places right after the overhead byte when encryption
+	/// is enabled. A counter that doesn't strictly increase within its
+	/// session prefix is treated as a replay and rejected.
+	pub fn with_cipher(mut self, cipher : ChaCha20Poly1305) -> PacketDecoder {
+		self.cipher = Some(cipher);
+		self
+	}
+
+	/// Expects the LEB128 varint length and fragment-index/fragment-total
+	/// header fields this crate's encoder emits in large-payload mode, and
+	/// reassembles fragmented messages. See [`PacketDecoder::with_max_payload_size`]
+	/// to bound a single frame's declared length and a reassembled
+	/// message's total size.
+	pub fn with_large_payload(mut self) -> PacketDecoder {
+		self.large_payload = true;
+		self
+	}
+
+	/// Overrides the allocation guard (`DEFAULT_MAX_PAYLOAD_SIZE` otherwise):
+	/// bounds a single frame's declared length and a reassembled message's
+	/// total size in large-payload mode, and bounds DEFLATE-inflated output
+	/// whenever compression is enabled, so a garbled or malicious length or
+	/// a zip-bomb compressed stream can't trigger an unbounded allocation
+	/// either way.
+	pub fn with_max_payload_size(mut self, max_payload_size : usize) -> PacketDecoder {
+		self.max_payload_size = max_payload_size;
+		self
+	}
+
+	/// Expects a flags byte this crate's encoder emits when compression is
+	/// enabled: its `FLAG_COMPRESSED` bit tells the decoder whether this
+	/// particular frame's payload needs DEFLATE-inflating (small payloads
+	/// are sent uncompressed even in this mode). Inflating happens after
+	/// CRC-verification and decryption, right before COBS-decoding.
+	pub fn with_compression(mut self) -> PacketDecoder {
+		self.compression = true;
+		self
+	}
+
+	/// Advances the frame state machine over `input`, one byte at a time,
+	/// stopping as soon as a frame completes or an error is detected.
+	/// Returns the resulting state plus how many bytes of `input` were
+	/// consumed to reach it; callers should re-feed any unconsumed tail.
+	pub fn parse_read_bytes(&mut self, input : &[u8]) -> (ParseState, usize) {
+		if input.is_empty() {
+			return (ParseState::NoData, 0);
+		}
+
+		for (i, &byte) in input.iter().enumerate() {
+			let consumed = i + 1;
+
+			match self.state {
+				DecodeState::FindStartByte => {
+					if byte == START_BYTE {
+						self.state = DecodeState::FindIdByte;
+					}
+				},
+				DecodeState::FindIdByte => {
+					self.id_byte = byte;
+					self.state = DecodeState::FindOverheadByte;
+				},
+				DecodeState::FindOverheadByte => {
+					self.overhead_byte = byte;
+					if self.cipher.is_some() {
+						self.nonce_bytes_read = 0;
+						self.state = DecodeState::FindNonce;
+					} else {
+						self.state = self.after_nonce_state();
+					}
+				},
+				DecodeState::FindNonce => {
+					self.nonce[self.nonce_bytes_read] = byte;
+					self.nonce_bytes_read += 1;
+
+					if self.nonce_bytes_read == self.nonce.len() {
+						self.state = self.after_nonce_state();
+					}
+				},
+				DecodeState::FindFlags => {
+					self.flags = byte;
+					self.state = if self.large_payload {
+						DecodeState::FindFragmentIndex
+					} else {
+						DecodeState::FindPayloadLength
+					};
+				},
+				DecodeState::FindFragmentIndex => {
+					match self.varint.push(byte) {
+						VarintOutcome::Done(value) => {
+							self.fragment_index = value;
+							self.state = DecodeState::FindFragmentTotal;
+						},
+						VarintOutcome::Pending => {},
+						VarintOutcome::Overflow => {
+							self.state = DecodeState::FindStartByte;
+							return (ParseState::PayloadError, consumed);
+						},
+					}
+				},
+				DecodeState::FindFragmentTotal => {
+					match self.varint.push(byte) {
+						VarintOutcome::Done(value) => {
+							self.fragment_total = value;
+							self.state = DecodeState::FindPayloadLength;
+						},
+						VarintOutcome::Pending => {},
+						VarintOutcome::Overflow => {
+							self.state = DecodeState::FindStartByte;
+							return (ParseState::PayloadError, consumed);
+						},
+					}
+				},
+				DecodeState::FindPayloadLength => {
+					let length = if self.large_payload {
+						match self.varint.push(byte) {
+							VarintOutcome::Done(value) => value,
+							VarintOutcome::Pending => continue,
+							VarintOutcome::Overflow => {
+								self.state = DecodeState::FindStartByte;
+								return (ParseState::PayloadError, consumed);
+							},
+						}
+					} else {
+						if byte == 0 || byte >= MAX_PACKET_SIZE {
+							self.state = DecodeState::FindStartByte;
+							return (ParseState::PayloadError, consumed);
+						}
+						byte as usize
+					};
+
+					if length == 0 || length > self.max_payload_size {
+						self.state = DecodeState::FindStartByte;
+						return (ParseState::PayloadError, consumed);
+					}
+
+					self.payload_length = length;
+					self.payload.clear();
+					self.state = DecodeState::FindPayload;
+				},
+				DecodeState::FindPayload => {
+					self.payload.push(byte);
+
+					if self.payload.len() == self.payload_length {
+						self.state = DecodeState::FindCrc;
+					}
+				},
+				DecodeState::FindCrc => {
+					let calculated_crc = self.crc.calculate(&self.payload, Some(self.payload_length));
+
+					if calculated_crc != byte {
+						self.state = DecodeState::FindStartByte;
+						return (ParseState::CrcError, consumed);
+					}
+
+					let decrypted = match &self.cipher {
+						Some(cipher) => {
+							match cipher.decrypt(Nonce::from_slice(&self.nonce), self.payload.as_slice()) {
+								Ok(plaintext) => plaintext,
+								Err(_) => {
+									self.state = DecodeState::FindStartByte;
+									return (ParseState::AuthError, consumed);
+								}
+							}
+						},
+						None => self.payload.clone(),
+					};
+
+					if self.cipher.is_some() && !self.accept_nonce() {
+						self.state = DecodeState::FindStartByte;
+						return (ParseState::AuthError, consumed);
+					}
+
+					let cobs_buffer = if self.flags & FLAG_COMPRESSED != 0 {
+						match inflate(&decrypted, self.max_payload_size) {
+							Ok(inflated) => inflated,
+							Err(_) => {
+								self.state = DecodeState::FindStartByte;
+								return (ParseState::PayloadError, consumed);
+							}
+						}
+					} else {
+						decrypted
+					};
+
+					self.payload = match decode_data_cobs(cobs_buffer, self.overhead_byte) {
+						Some(decoded) => decoded,
+						None => {
+							self.state = DecodeState::FindStartByte;
+							return (ParseState::PayloadError, consumed);
+						}
+					};
+					self.state = DecodeState::FindStopByte;
+				},
+				DecodeState::FindStopByte => {
+					self.state = DecodeState::FindStartByte;
+
+					if byte != STOP_BYTE {
+						return (ParseState::StopByteError, consumed);
+					}
+
+					if !self.large_payload || self.fragment_total <= 1 {
+						return (ParseState::DataReady, consumed);
+					}
+
+					match self.reassemble() {
+						ReassembleOutcome::Complete => return (ParseState::DataReady, consumed),
+						ReassembleOutcome::Pending => {},
+						ReassembleOutcome::Rejected => return (ParseState::PayloadError, consumed),
+					}
+				},
+			}
+		}
+
+		(ParseState::InProgress, input.len())
+	}
+
+	/// Checks the just-decrypted frame's nonce against `replay_guard` and
+	/// updates it. A session prefix not seen before is trusted on first
+	/// sight (the peer restarted or this is the first frame); within a
+	/// known session prefix, the counter must strictly increase, so a
+	/// captured-and-replayed or duplicated frame is rejected.
+	fn accept_nonce(&mut self) -> bool {
+		let mut session_prefix = [0u8; 8];
+		session_prefix.copy_from_slice(&self.nonce[..8]);
+
+		let mut counter_bytes = [0u8; 4];
+		counter_bytes.copy_from_slice(&self.nonce[8..]);
+		let counter = u32::from_le_bytes(counter_bytes);
+
+		let fresh = match self.replay_guard {
+			Some((seen_prefix, seen_counter)) if seen_prefix == session_prefix => counter > seen_counter,
+			_ => true,
+		};
+
+		if fresh {
+			self.replay_guard = Some((session_prefix, counter));
+		}
+
+		fresh
+	}
+
+	fn after_nonce_state(&self) -> DecodeState {
+		if self.compression {
+			DecodeState::FindFlags
+		} else if self.large_payload {
+			DecodeState::FindFragmentIndex
+		} else {
+			DecodeState::FindPayloadLength
+		}
+	}
+
+	/// Folds the just-completed fragment into its message's reassembly
+	/// buffer, keyed by `id_byte`.
+	fn reassemble(&mut self) -> ReassembleOutcome {
+		let entry = self.reassembly.entry(self.id_byte).or_insert_with(|| Reassembly {
+			fragment_total : self.fragment_total,
+			next_index : 0,
+			buffer : Vec::new(),
+		});
+
+		if self.fragment_index != entry.next_index
+			|| self.fragment_total != entry.fragment_total
+			|| entry.buffer.len() + self.payload.len() > self.max_payload_size
+		{
+			self.reassembly.remove(&self.id_byte);
+			self.payload.clear();
+			return ReassembleOutcome::Rejected;
+		}
+
+		entry.buffer.append(&mut self.payload);
+		entry.next_index += 1;
+
+		if entry.next_index == entry.fragment_total {
+			let Reassembly { buffer, .. } = self.reassembly.remove(&self.id_byte).unwrap();
+			self.payload = buffer;
+			ReassembleOutcome::Complete
+		} else {
+			ReassembleOutcome::Pending
+		}
+	}
+
+	/// The payload of the most recently completed (and, if fragmented,
+	/// fully reassembled) frame, already decrypted (if applicable) and
+	/// COBS-decoded.
+	pub fn payload(&self) -> &[u8] {
+		&self.payload
+	}
+
+	/// The id byte of the most recently completed frame.
+	pub fn id_byte(&self) -> u8 {
+		self.id_byte
+	}
+}
+
+/// Inflates `data` a chunk at a time, aborting as soon as the running
+/// output total would exceed `max_output`. Without this, a tiny
+/// malicious/garbled DEFLATE stream well within the wire length cap could
+/// expand into a multi-GB allocation (a zip bomb) before `max_payload_size`
+/// ever got a chance to reject it.
+fn inflate(data : &[u8], max_output : usize) -> std::io::Result<Vec<u8>> {
+	let mut decoder = ZlibDecoder::new(data);
+	let mut out = Vec::new();
+	let mut chunk = [0u8; 8192];
+
+	loop {
+		let read = decoder.read(&mut chunk)?;
+		if read == 0 {
+			break;
+		}
+
+		if out.len() + read > max_output {
+			return Err(std::io::Error::other("inflated payload exceeds max_payload_size"));
+		}
+
+		out.extend_from_slice(&chunk[..read]);
+	}
+
+	Ok(out)
+}
+
+
+/// Inverse of `encode_data_cobs`: walks the chain of codes starting with
+/// `overhead_byte` (the first code, carried in the frame header), copying
+/// each block's literal bytes through and re-inserting a `START_BYTE`
+/// between blocks that were closed by a real occurrence. A block closed by
+/// a forced split (`code == COBS_MAX_BLOCK`) gets no `START_BYTE` inserted,
+/// since no real occurrence was removed there. Returns `None` if a code is
+/// `0`, which is never produced by `encode_data_cobs` and would otherwise
+/// underflow the literal-length subtraction below; `overhead_byte` in
+/// particular arrives straight off the wire with no CRC coverage, so this
+/// has to be treated as ordinary malformed input, not a panic.
+fn decode_data_cobs(data : Vec<u8>, overhead_byte : u8) -> Option<Vec<u8>> {
+	let mut out = Vec::with_capacity(data.len());
+	let mut code = overhead_byte;
+	let mut pos = 0usize;
+
+	loop {
+		let literal_len = code.checked_sub(1)? as usize;
+		if pos + literal_len > data.len() { break; }
+
+		out.extend_from_slice(&data[pos..pos + literal_len]);
+		pos += literal_len;
+
+		if pos >= data.len() { break; }
+
+		let next_code = data[pos];
+		if code != COBS_MAX_BLOCK {
+			out.push(START_BYTE);
+		}
+		code = next_code;
+		pos += 1;
+	}
+
+	Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::encode_data_cobs;
+
+	fn cobs_round_trip(data : &[u8]) -> Vec<u8> {
+		let (first_code, encoded) = encode_data_cobs(data);
+		decode_data_cobs(encoded, first_code).unwrap()
+	}
+
+	#[test]
+	fn cobs_round_trips_empty_and_delimiter_free_data() {
+		assert_eq!(cobs_round_trip(&[]), Vec::<u8>::new());
+		assert_eq!(cobs_round_trip(&[1, 2, 3]), vec![1, 2, 3]);
+	}
+
+	#[test]
+	fn cobs_round_trips_data_containing_the_start_byte() {
+		let data = vec![1, START_BYTE, 2, START_BYTE, START_BYTE, 3];
+		assert_eq!(cobs_round_trip(&data), data);
+	}
+
+	#[test]
+	fn cobs_round_trips_a_payload_over_255_bytes_with_a_start_byte_past_that_point() {
+		//the original u8-indexed implementation corrupted exactly this shape:
+		//a payload longer than a single COBS block with a delimiter near the
+		//end of it
+		let mut data = vec![0xAAu8; 300];
+		data[280] = START_BYTE;
+
+		assert_eq!(cobs_round_trip(&data), data);
+	}
+
+	#[test]
+	fn cobs_round_trips_a_long_run_of_start_bytes() {
+		let data = vec![START_BYTE; 600];
+		assert_eq!(cobs_round_trip(&data), data);
+	}
+
+	#[test]
+	fn parse_read_bytes_decodes_a_hand_built_frame() {
+		let payload = b"hello";
+		let (overhead_byte, cobs_buffer) = encode_data_cobs(payload);
+		let crc = CRC::new(0x9B).calculate(&cobs_buffer, None);
+
+		let mut frame = vec![START_BYTE, 0, overhead_byte, cobs_buffer.len() as u8];
+		frame.extend_from_slice(&cobs_buffer);
+		frame.push(crc);
+		frame.push(STOP_BYTE);
+
+		let mut decoder = PacketDecoder::new(CRC::new(0x9B));
+		let (state, consumed) = decoder.parse_read_bytes(&frame);
+
+		assert_eq!(state, ParseState::DataReady);
+		assert_eq!(consumed, frame.len());
+		assert_eq!(decoder.payload(), payload);
+	}
+
+	#[test]
+	fn parse_read_bytes_can_be_fed_one_byte_at_a_time() {
+		let payload = b"split across reads";
+		let (overhead_byte, cobs_buffer) = encode_data_cobs(payload);
+		let crc = CRC::new(0x9B).calculate(&cobs_buffer, None);
+
+		let mut frame = vec![START_BYTE, 7, overhead_byte, cobs_buffer.len() as u8];
+		frame.extend_from_slice(&cobs_buffer);
+		frame.push(crc);
+		frame.push(STOP_BYTE);
+
+		let mut decoder = PacketDecoder::new(CRC::new(0x9B));
+		let mut last_state = ParseState::NoData;
+
+		for &byte in &frame {
+			let (state, _) = decoder.parse_read_bytes(&[byte]);
+			last_state = state;
+		}
+
+		assert_eq!(last_state, ParseState::DataReady);
+		assert_eq!(decoder.id_byte(), 7);
+		assert_eq!(decoder.payload(), payload);
+	}
+
+	#[test]
+	fn parse_read_bytes_rejects_a_bad_crc() {
+		let payload = b"hi";
+		let (overhead_byte, cobs_buffer) = encode_data_cobs(payload);
+
+		let mut frame = vec![START_BYTE, 0, overhead_byte, cobs_buffer.len() as u8];
+		frame.extend_from_slice(&cobs_buffer);
+		frame.push(0xFF); //wrong CRC
+		frame.push(STOP_BYTE);
+
+		let mut decoder = PacketDecoder::new(CRC::new(0x9B));
+		let (state, _) = decoder.parse_read_bytes(&frame);
+
+		assert_eq!(state, ParseState::CrcError);
+	}
+
+	#[test]
+	fn parse_read_bytes_rejects_a_bad_stop_byte() {
+		let payload = b"hi";
+		let (overhead_byte, cobs_buffer) = encode_data_cobs(payload);
+		let crc = CRC::new(0x9B).calculate(&cobs_buffer, None);
+
+		let mut frame = vec![START_BYTE, 0, overhead_byte, cobs_buffer.len() as u8];
+		frame.extend_from_slice(&cobs_buffer);
+		frame.push(crc);
+		frame.push(0x00); //not STOP_BYTE
+
+		let mut decoder = PacketDecoder::new(CRC::new(0x9B));
+		let (state, _) = decoder.parse_read_bytes(&frame);
+
+		assert_eq!(state, ParseState::StopByteError);
+	}
+
+	#[test]
+	fn a_zero_overhead_byte_is_a_payload_error_not_a_panic() {
+		//overhead_byte isn't covered by the CRC, so line noise flipping it to
+		//0 must not reach the `code - 1` subtraction in decode_data_cobs
+		let payload = b"hi";
+		let (_, cobs_buffer) = encode_data_cobs(payload);
+		let crc = CRC::new(0x9B).calculate(&cobs_buffer, None);
+
+		let mut frame = vec![START_BYTE, 0, 0, cobs_buffer.len() as u8];
+		frame.extend_from_slice(&cobs_buffer);
+		frame.push(crc);
+		frame.push(STOP_BYTE);
+
+		let mut decoder = PacketDecoder::new(CRC::new(0x9B));
+		let (state, _) = decoder.parse_read_bytes(&frame);
+
+		assert_eq!(state, ParseState::PayloadError);
+	}
+
+	#[test]
+	fn varint_overflow_in_the_length_field_is_a_payload_error_not_a_panic() {
+		let mut decoder = PacketDecoder::new(CRC::new(0x9B)).with_large_payload().with_max_payload_size(1024);
+
+		//start byte, id, overhead, then fragment index 0 and fragment total 1
+		//so the run below lands in the length varint
+		let mut frame = vec![START_BYTE, 0, 1, 0, 1];
+		//10 continuation bytes reach VarintReader's cap for a 64-bit usize
+		//without terminating
+		frame.extend(std::iter::repeat_n(0x80, 10));
+
+		let (state, _) = decoder.parse_read_bytes(&frame);
+		assert_eq!(state, ParseState::PayloadError);
+	}
+
+	#[test]
+	fn fragmentation_rejects_an_out_of_order_fragment() {
+		let mut decoder = PacketDecoder::new(CRC::new(0x9B)).with_large_payload().with_max_payload_size(1024);
+
+		let build = |fragment_index : usize, fragment_total : usize, payload : &[u8]| {
+			let (overhead_byte, cobs_buffer) = encode_data_cobs(payload);
+			let crc = CRC::new(0x9B).calculate(&cobs_buffer, None);
+
+			let mut frame = vec![START_BYTE, 0, overhead_byte];
+			frame.extend_from_slice(&crate::varint::encode(fragment_index));
+			frame.extend_from_slice(&crate::varint::encode(fragment_total));
+			frame.extend_from_slice(&crate::varint::encode(cobs_buffer.len()));
+			frame.extend_from_slice(&cobs_buffer);
+			frame.push(crc);
+			frame.push(STOP_BYTE);
+			frame
+		};
+
+		//fragment 1 arrives before fragment 0 ever did
+		let (state, _) = decoder.parse_read_bytes(&build(1, 2, b"second"));
+		assert_eq!(state, ParseState::PayloadError);
+	}
+
+	#[test]
+	fn inflate_aborts_once_the_output_would_exceed_the_cap() {
+		let huge = vec![0u8; 1_000_000];
+		let compressed = crate::deflate(&huge);
+
+		let result = inflate(&compressed, 1024);
+		assert!(result.is_err());
+	}
+}
+
@@ -1,49 +1,83 @@
-use std::{io::Read, num::Wrapping};
-use std::mem::transmute_copy;
-use serialport::{Error, SerialPort};
+use std::collections::HashMap;
+use std::io::Write;
+
+use chacha20poly1305::{
+	aead::{rand_core::RngCore, Aead, OsRng},
+	ChaCha20Poly1305, KeyInit, Nonce,
+};
+use flate2::{write::ZlibEncoder, Compression};
+use serialport::{Error, ErrorKind, SerialPort};
+use zerocopy::{AsBytes, FromBytes};
 
 mod crc;
 use crc::CRC;
 
-#[derive(Debug)]
-enum TransferStatus {
+mod decoder;
+use decoder::{PacketDecoder, ParseState, FLAG_COMPRESSED};
+
+mod varint;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferStatus {
 	Continue = 3,
 	NewData = 2,
 	NoData = 1,
 	CrcError = 0,
 	PayloadError = -1,
 	StopByteError = -2,
+	AuthError = -3,
+	/// `poll` received a well-formed frame whose id has no registered handler.
+	UnknownId = -4,
 }
 
-#[derive(Debug)]
-enum TransferState {
-	FindStartByte = 0,
-	FindIdByte = 1,
-	FindOverheadByte = 2,
-	FindPayloadLength = 3,
-	FindPayload = 4,
-	FindCrc = 5,
-	FindStopByte = 6,
+/// Byte order the wire payload is encoded in. `send`/`available` convert
+/// between this and the host's native order so a little-endian MCU and a
+/// big-endian host agree on the bytes of a single multi-byte scalar `T`
+/// (a `u32`, an `f64`, ...).
+///
+/// This is a whole-payload byte reversal: it reverses the bytes of `T`
+/// as a unit, not the bytes of each element within it. For a `T` made of
+/// more than one value - an array, a struct, anything wider than one
+/// scalar - this reverses element order along with byte order within
+/// each element, which is almost never what's wanted. Such `T` should
+/// keep `Endianness::Native` and do their own per-field/per-element byte
+/// order handling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endianness {
+	Native,
+	Little,
+	Big,
 }
 
 const START_BYTE : u8 = 0x7E;
 const STOP_BYTE : u8 = 0x81;
 
-const MAX_PACKET_SIZE : u8 = 0xFE;
-
+/// Default cap on a reassembled large-payload message, and on any single
+/// frame's declared length in that mode, so a garbled or malicious length
+/// prefix can't make `available` allocate without bound.
+pub(crate) const DEFAULT_MAX_PAYLOAD_SIZE : usize = 4 * 1024 * 1024;
 
+/// A handler registered with `register_handler`, invoked with a freshly
+/// decoded payload's raw bytes. Returns whether the payload actually
+/// decoded as the handler's expected type, so `poll` can report a
+/// decode failure the same way `available` does.
+type Handler = Box<dyn FnMut(&[u8]) -> bool>;
 
 pub struct SerialTransfer {
 	crc : CRC,
+	decoder : PacketDecoder,
+	cipher : Option<ChaCha20Poly1305>,
+	nonce_prefix : [u8; 8],
+	nonce_counter : Option<u32>,
+	fragment_mtu : Option<usize>,
+	max_payload_size : usize,
+	compression_threshold : Option<usize>,
+
+	handlers : HashMap<u8, Handler>,
 
 	serialport : Box<dyn SerialPort>,
 	status : TransferStatus,
-	transfer_state : TransferState,
-
-	id_byte : u8,
-	overhead_byte : u8,
-	payload_length : u8,
-	payload : Vec<u8>,
+	endianness : Endianness,
 }
 
 impl SerialTransfer {
@@ -51,180 +85,659 @@ impl SerialTransfer {
 	pub fn new(port : Box<dyn SerialPort>) -> SerialTransfer {
 		SerialTransfer {
 			crc : CRC::new(0x9B),
+			decoder : PacketDecoder::new(CRC::new(0x9B)),
+			cipher : None,
+			nonce_prefix : [0; 8],
+			nonce_counter : Some(0),
+			fragment_mtu : None,
+			max_payload_size : DEFAULT_MAX_PAYLOAD_SIZE,
+			compression_threshold : None,
+			handlers : HashMap::new(),
 
 			status : TransferStatus::Continue,
-			transfer_state : TransferState::FindStartByte,
 			serialport : port,
+			endianness : Endianness::Native,
+		}
+	}
+
+	/// Sets the wire byte order, for links where the peer's native
+	/// endianness differs from the host's. Defaults to [`Endianness::Native`].
+	pub fn with_endianness(mut self, endianness : Endianness) -> SerialTransfer {
+		self.endianness = endianness;
+		self
+	}
+
+	/// Enables ChaCha20-Poly1305 encryption of every payload with `key`.
+	/// Each frame's 96-bit nonce is an 8-byte random session prefix (chosen
+	/// fresh here, carried on the wire) followed by a 4-byte counter that
+	/// increments every frame; this keeps nonces unique across process
+	/// restarts without asking the caller to persist anything, as long as
+	/// `key` itself is never reused with a different nonce scheme. The
+	/// decoder rejects a repeated or out-of-order counter within the same
+	/// session prefix as a replay, and a tampered, misdecrypted, or
+	/// replayed frame surfaces as [`TransferStatus::AuthError`] instead of
+	/// being delivered.
+	pub fn with_encryption(mut self, key : [u8; 32]) -> SerialTransfer {
+		self.cipher = Some(ChaCha20Poly1305::new((&key).into()));
+		OsRng.fill_bytes(&mut self.nonce_prefix);
+		self.nonce_counter = Some(0);
+		self.rebuild_decoder();
+		self
+	}
 
-			id_byte: 0,
-			overhead_byte: 0,
-			payload_length: 0,
-			payload: Vec::new(),
+	/// Lifts the 254-byte payload cap: the length header becomes an LEB128
+	/// varint, and `send` splits any payload larger than `mtu` bytes into
+	/// ordered fragments (each its own CRC-checked frame) that `available`
+	/// reassembles on the other end, keyed by `id_byte`. Use
+	/// [`SerialTransfer::with_max_payload_size`] to change the allocation
+	/// guard from its default of `DEFAULT_MAX_PAYLOAD_SIZE`.
+	pub fn with_fragmentation(mut self, mtu : usize) -> SerialTransfer {
+		self.fragment_mtu = Some(mtu);
+		self.rebuild_decoder();
+		self
+	}
+
+	/// Overrides the allocation guard (`DEFAULT_MAX_PAYLOAD_SIZE` otherwise):
+	/// bounds a single frame's declared length and a reassembled message's
+	/// total size in large-payload mode (see
+	/// [`SerialTransfer::with_fragmentation`]), and bounds DEFLATE-inflated
+	/// output whenever [`SerialTransfer::with_compression`] is enabled.
+	pub fn with_max_payload_size(mut self, max_payload_size : usize) -> SerialTransfer {
+		self.max_payload_size = max_payload_size;
+		self.rebuild_decoder();
+		self
+	}
+
+	/// DEFLATEs the COBS-encoded payload whenever it exceeds `threshold`
+	/// bytes, signalled to the peer by a flag bit so payloads left
+	/// uncompressed (below the threshold, where compression overhead isn't
+	/// worth it) still decode correctly.
+	pub fn with_compression(mut self, threshold : usize) -> SerialTransfer {
+		self.compression_threshold = Some(threshold);
+		self.rebuild_decoder();
+		self
+	}
+
+	fn rebuild_decoder(&mut self) {
+		//max_payload_size bounds decompression too, so it applies whenever
+		//compression or large-payload mode (or both) are enabled, not just
+		//when fragmentation is on
+		let mut decoder = PacketDecoder::new(CRC::new(0x9B)).with_max_payload_size(self.max_payload_size);
+
+		if let Some(cipher) = &self.cipher {
+			decoder = decoder.with_cipher(cipher.clone());
+		}
+
+		if self.compression_threshold.is_some() {
+			decoder = decoder.with_compression();
 		}
+
+		if self.fragment_mtu.is_some() {
+			decoder = decoder.with_large_payload();
+		}
+
+		self.decoder = decoder;
 	}
 
-	pub fn send<T : Sized, const COUNT: usize>(&mut self, data : T) -> Result<(),Error> {
-		let buffer : [u8;COUNT] = unsafe { transmute_copy(&data) };
-		let buffer = buffer.to_vec();
+	/// Binds `handler` to `id`: frames `poll` routes to `id` are decoded as
+	/// `T` and handed to `handler`, instead of going through the single
+	/// generic `available::<T>()`. This is what lets one link multiplex
+	/// several distinct message types (telemetry, commands, acks) over the
+	/// same serial port.
+	pub fn register_handler<T : FromBytes + 'static>(&mut self, id : u8, mut handler : impl FnMut(T) + 'static) {
+		self.handlers.insert(id, Box::new(move |payload : &[u8]| {
+			match T::read_from(payload) {
+				Some(value) => {
+					handler(value);
+					true
+				},
+				None => false,
+			}
+		}));
+	}
+
+	/// The status set by the most recent `available`/`poll` call that
+	/// completed a frame or hit an error. `send` does not change it, and a
+	/// call that finds no complete frame on the port leaves it as it was.
+	pub fn status(&self) -> TransferStatus {
+		self.status
+	}
+
+	/// Encodes `data` into a single framed, COBS-stuffed, CRC-terminated
+	/// packet with id `0` (encrypting it first if encryption is enabled)
+	/// without touching the port. Does not fragment: for payloads larger
+	/// than the configured MTU, use [`SerialTransfer::send`], which splits
+	/// across multiple frames. `send` is just this (per fragment) plus a
+	/// write. Errors if encryption is enabled and the nonce counter has
+	/// been exhausted (see [`SerialTransfer::with_encryption`]).
+	pub fn create_msg<T : AsBytes>(&mut self, data : T) -> Result<Vec<u8>,Error> {
+		let mut raw = data.as_bytes().to_vec();
+		self.convert_endianness(&mut raw);
+		self.build_frame(&raw, 0, 1, 0)
+	}
+
+	/// Same as [`SerialTransfer::create_msg`], but copies the result into a
+	/// caller-sized `[u8; N]` instead of returning a `Vec`, for callers that
+	/// would rather size the buffer themselves. Still builds the packet via
+	/// `create_msg`, which allocates internally, and this crate depends on
+	/// `std` throughout - this isn't a `no_std`-usable path. Truncates to
+	/// `N` bytes if the packet is longer; the returned length is the number
+	/// of bytes actually written.
+	pub fn create_msg_array<T : AsBytes, const N : usize>(&mut self, data : T) -> Result<([u8; N], usize),Error> {
+		let packet = self.create_msg(data)?;
+
+		let mut out = [0u8; N];
+		let len = packet.len().min(N);
+		out[..len].copy_from_slice(&packet[..len]);
+
+		Ok((out, len))
+	}
 
-		//find first START_BYTE occurence in packet data
-		let overflow_byte = match buffer.iter().position(|&x| x == START_BYTE) {
-			Some(index) => (index) as u8,
-			None => 0xFF,
+	pub fn send<T : AsBytes>(&mut self, data : T) -> Result<(),Error> {
+		self.send_with_id(0, data)
+	}
+
+	/// Same as [`SerialTransfer::send`], but tags the frame(s) with `id` so
+	/// the peer's registered handler for that id (see
+	/// [`SerialTransfer::register_handler`]) receives it.
+	pub fn send_with_id<T : AsBytes>(&mut self, id : u8, data : T) -> Result<(),Error> {
+		let mut raw = data.as_bytes().to_vec();
+		self.convert_endianness(&mut raw);
+
+		match self.fragment_mtu {
+			Some(mtu) if raw.len() > mtu => {
+				let chunks : Vec<&[u8]> = raw.chunks(mtu).collect();
+				let fragment_total = chunks.len();
+
+				for (fragment_index, chunk) in chunks.into_iter().enumerate() {
+					let packet = self.build_frame(chunk, fragment_index, fragment_total, id)?;
+					self.serialport.write(&packet)?;
+				}
+			},
+			_ => {
+				let packet = self.build_frame(&raw, 0, 1, id)?;
+				self.serialport.write(&packet)?;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Frames one physical packet out of already endianness-converted raw
+	/// bytes: COBS-stuffs it, compresses it if it's worth it, encrypts it
+	/// if encryption is enabled, and wraps it with the
+	/// id/overhead/[nonce]/[flags]/[fragment]/length header, CRC and
+	/// start/stop bytes. `fragment_total == 1` marks an unfragmented
+	/// message. Errors with `ErrorKind::InvalidInput` rather than wrapping
+	/// the nonce counter back to a reused value once it's exhausted.
+	fn build_frame(&mut self, raw : &[u8], fragment_index : usize, fragment_total : usize, id : u8) -> Result<Vec<u8>,Error> {
+		//encode data with COBS; the first chain code travels in the header's
+		//overhead byte, the rest ride along embedded in the encoded buffer
+		let (overflow_byte, cobs_buffer) = encode_data_cobs(raw);
+
+		let (compressed_buffer, flags) = match self.compression_threshold {
+			Some(threshold) if cobs_buffer.len() > threshold => (deflate(&cobs_buffer), FLAG_COMPRESSED),
+			_ => (cobs_buffer, 0u8),
 		};
 
-		//encode data with COBS
-		let buffer = self.encode_data_cobs(buffer);
+		let (buffer, nonce) = match &self.cipher {
+			Some(cipher) => {
+				let counter = self.nonce_counter.ok_or_else(|| Error::new(
+					ErrorKind::InvalidInput,
+					"encryption nonce counter exhausted; re-key with SerialTransfer::with_encryption",
+				))?;
+				self.nonce_counter = counter.checked_add(1);
+
+				let mut full_nonce = [0u8; 12];
+				full_nonce[..8].copy_from_slice(&self.nonce_prefix);
+				full_nonce[8..].copy_from_slice(&counter.to_le_bytes());
+
+				let ciphertext = cipher.encrypt(Nonce::from_slice(&full_nonce), compressed_buffer.as_slice())
+					.expect("chacha20poly1305 encryption only fails on invalid key/nonce lengths");
+
+				(ciphertext, Some(full_nonce))
+			},
+			None => (compressed_buffer, None),
+		};
 
 		//calculate CRC (Error Detection Code)
 		let crc = self.crc.calculate(&buffer,None);
 
 		let mut packet : Vec<u8> = Vec::new();
 		packet.push(START_BYTE);
-		packet.push(0);
+		packet.push(id);
 		packet.push(overflow_byte);
-		packet.push(buffer.len() as u8);
-		packet.append(&mut buffer.clone());
+		if let Some(nonce) = nonce {
+			packet.extend_from_slice(&nonce);
+		}
+
+		if self.compression_threshold.is_some() {
+			packet.push(flags);
+		}
+
+		if self.fragment_mtu.is_some() {
+			packet.extend_from_slice(&varint::encode(fragment_index));
+			packet.extend_from_slice(&varint::encode(fragment_total));
+			packet.extend_from_slice(&varint::encode(buffer.len()));
+		} else {
+			packet.push(buffer.len() as u8);
+		}
+
+		packet.extend_from_slice(&buffer);
 		packet.push(crc);
 		packet.push(STOP_BYTE);
 
-		self.serialport.write(&packet)?;
-
-		Ok(())
+		Ok(packet)
 	}
 
-	pub fn available<T : Sized, const COUNT: usize>(&mut self) -> Result<Option<T>,Error> {
+	pub fn available<T : FromBytes>(&mut self) -> Result<Option<T>,Error> {
+		while let Some(state) = self.next_frame_state()? {
+			self.status = Self::status_for(state);
 
-		while self.serialport.bytes_to_read()? > 0 {
-			//show state and status in test only
+			if state == ParseState::DataReady {
+				let mut payload = self.decoder.payload().to_vec();
+				self.convert_endianness(&mut payload);
 
-			let mut byte : [u8;1] = [0;1];
-			self.serialport.read(&mut byte)?;
-
-			match self.transfer_state {
-				TransferState::FindStartByte => {
-					if byte[0] == START_BYTE {
-						self.transfer_state = TransferState::FindIdByte; 
-					}
-				},
-				TransferState::FindIdByte => {
-					self.id_byte = byte[0];
-					self.transfer_state = TransferState::FindOverheadByte;	
-				},
-				TransferState::FindOverheadByte => {
-					self.overhead_byte = byte[0];
-					self.transfer_state = TransferState::FindPayloadLength;
-				},
-				TransferState::FindPayloadLength => {
-					if byte[0] > 0 && byte[0] < MAX_PACKET_SIZE {
-						self.payload_length = byte[0];
-						self.transfer_state = TransferState::FindPayload;
-						self.payload.clear();
-					}else{
-						self.transfer_state = TransferState::FindStartByte;
+				return match T::read_from(payload.as_slice()) {
+					Some(dst) => Ok(Some(dst)),
+					None => {
 						self.status = TransferStatus::PayloadError;
+						Ok(None)
 					}
-				},
-				TransferState::FindPayload => {
-					if self.payload.len() < self.payload_length.into() {
-						self.payload.push(byte[0]);
-	
-						if self.payload.len() == self.payload_length.into() {
-
-							self.transfer_state = TransferState::FindCrc;
-						} else {
-							self.transfer_state = TransferState::FindPayload;
-						}
-					}
-				},
-				TransferState::FindCrc => {
-					
-					let calculated_crc = self.crc.calculate(&self.payload,Some(self.payload_length));
-					let received_crc = byte[0];
-
-					//decode data with COBS
-					self.payload = self.decode_data_cobs(self.payload.clone(),self.overhead_byte);
-
-					if calculated_crc == received_crc {
-						self.transfer_state = TransferState::FindStopByte;
-					} else {
-						self.transfer_state = TransferState::FindStartByte;
-						self.status = TransferStatus::CrcError;
-					}
-				},
-				TransferState::FindStopByte => {
-					self.transfer_state = TransferState::FindStartByte;
-	
-					if byte[0] == STOP_BYTE {
-						self.transfer_state = TransferState::FindStartByte;
-						self.status = TransferStatus::NewData;
-						let buffer_conversion : Result<[u8;COUNT],Vec<u8>> = self.payload.clone().try_into();
-
-						match buffer_conversion {
-							Ok(buffer) => {
-								let dst : T = unsafe { transmute_copy(&buffer) };
-								return Ok(Some(dst))
-							}
-							Err(_) => {
-								self.status = TransferStatus::PayloadError;
-							}
-						}
-					} else {
-						self.status = TransferStatus::StopByteError;
-					}
-				},
+				};
 			}
 		}
 
 		Ok(None)
 	}
 
-	fn encode_data_cobs(&mut self, mut data : Vec<u8>) -> Vec<u8> {
-		//find last byte
-		let mut last_byte_index : Option<usize> = None;
-		for i in (0..data.len()).rev() {
-			if data[i] == START_BYTE {
-				last_byte_index = Some(i);
-				break;
+	/// Reads and dispatches every complete frame currently available on the
+	/// port to its registered handler (see
+	/// [`SerialTransfer::register_handler`]), decoding each into that
+	/// handler's expected type. Frames for an id with no registered handler
+	/// set `status()` to [`TransferStatus::UnknownId`] and are dropped; a
+	/// frame whose payload doesn't decode as its handler's expected type
+	/// sets `status()` to [`TransferStatus::PayloadError`], just like
+	/// `available` does for the same failure.
+	pub fn poll(&mut self) -> Result<(),Error> {
+		while let Some(state) = self.next_frame_state()? {
+			self.status = Self::status_for(state);
+
+			if state == ParseState::DataReady {
+				let id = self.decoder.id_byte();
+				let mut payload = self.decoder.payload().to_vec();
+				self.convert_endianness(&mut payload);
+
+				match self.handlers.get_mut(&id) {
+					Some(handler) => {
+						if !handler(&payload) {
+							self.status = TransferStatus::PayloadError;
+						}
+					},
+					None => self.status = TransferStatus::UnknownId,
+				}
 			}
 		}
 
-		match last_byte_index {
-			Some(index) => {
-				let mut reference_index : u8 = index as u8;
+		Ok(())
+	}
 
-				for i in (0..data.len() as u8).rev() {
-					if data[i as usize] == START_BYTE {
-						let (new_reference_index, _overflowed) = reference_index.overflowing_sub(i);
-						data[i as usize] = new_reference_index as u8;
-						reference_index = i;
-					}
-				}
+	/// Reads bytes off the port one at a time until a frame completes (in
+	/// any `ParseState`, success or error) or the port runs dry.
+	fn next_frame_state(&mut self) -> Result<Option<ParseState>,Error> {
+		while self.serialport.bytes_to_read()? > 0 {
+			let mut byte : [u8;1] = [0;1];
+			self.serialport.read(&mut byte)?;
 
-				data
-			},
-			None => {
-				data
+			let (state, _consumed) = self.decoder.parse_read_bytes(&byte);
+
+			if state != ParseState::InProgress && state != ParseState::NoData {
+				return Ok(Some(state));
 			}
 		}
-	}
 
-	fn decode_data_cobs(&mut self, mut data : Vec<u8>, overhead_byte : u8) -> Vec<u8> {
-		let mut reference_index = overhead_byte;
-		let mut overflowed;
+		Ok(None)
+	}
 
-		while reference_index < data.len() as u8 {
-			let offset = data[reference_index as usize];
-			data[reference_index as usize] = START_BYTE;
-			(reference_index, overflowed) = reference_index.overflowing_add(offset);
-			if overflowed { break; }
+	fn status_for(state : ParseState) -> TransferStatus {
+		match state {
+			ParseState::DataReady => TransferStatus::NewData,
+			ParseState::CrcError => TransferStatus::CrcError,
+			ParseState::PayloadError => TransferStatus::PayloadError,
+			ParseState::StopByteError => TransferStatus::StopByteError,
+			ParseState::AuthError => TransferStatus::AuthError,
+			ParseState::InProgress | ParseState::NoData => TransferStatus::Continue,
 		}
+	}
 
-		data
+	/// Reverses `buffer` in place when `self.endianness` names an order
+	/// that differs from the host's native order, so both `send` and
+	/// `available` see/produce bytes in the configured wire order.
+	fn convert_endianness(&self, buffer : &mut Vec<u8>) {
+		let host_is_big_endian = cfg!(target_endian = "big");
+
+		let swap = match self.endianness {
+			Endianness::Native => false,
+			Endianness::Big => !host_is_big_endian,
+			Endianness::Little => host_is_big_endian,
+		};
+
+		if swap {
+			buffer.reverse();
+		}
 	}
 
 	pub fn flush(&mut self) -> Result<(),Error> {
 		self.serialport.flush()?;
 		Ok(())
 	}
-}
\ No newline at end of file
+}
+
+
+pub(crate) fn deflate(data : &[u8]) -> Vec<u8> {
+	let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+	encoder.write_all(data).expect("writing to an in-memory buffer cannot fail");
+	encoder.finish().expect("writing to an in-memory buffer cannot fail")
+}
+
+/// Longest run of literal (non-`START_BYTE`) bytes a single COBS chain code
+/// can span. A run hitting this length without a real `START_BYTE` forces a
+/// split, so every code still fits in a `u8` no matter how long `data` is.
+const COBS_MAX_BLOCK : u8 = 0xFF;
+
+/// Replaces every `START_BYTE` occurrence in `data` with a chain of codes
+/// pointing to the next occurrence (classic COBS), forcing a split every
+/// `COBS_MAX_BLOCK - 1` literal bytes so the scheme works for `data` of any
+/// length rather than only frames under 255 bytes. The first code in the
+/// chain is returned separately so it can travel in the frame header's
+/// overhead byte, matching this crate's existing wire layout; the rest are
+/// embedded in the returned buffer and reconstructed by `decode_data_cobs`.
+pub(crate) fn encode_data_cobs(data : &[u8]) -> (u8, Vec<u8>) {
+	let mut out : Vec<u8> = Vec::with_capacity(data.len() + data.len() / (COBS_MAX_BLOCK as usize - 1) + 1);
+	let mut first_code : u8 = 1;
+	let mut code_index : Option<usize> = None;
+	let mut code : u8 = 1;
+
+	for &byte in data {
+		if byte == START_BYTE {
+			match code_index {
+				Some(i) => out[i] = code,
+				None => first_code = code,
+			}
+			code_index = Some(out.len());
+			out.push(0);
+			code = 1;
+		} else {
+			out.push(byte);
+			code += 1;
+
+			if code == COBS_MAX_BLOCK {
+				match code_index {
+					Some(i) => out[i] = code,
+					None => first_code = code,
+				}
+				code_index = Some(out.len());
+				out.push(0);
+				code = 1;
+			}
+		}
+	}
+
+	match code_index {
+		Some(i) => out[i] = code,
+		None => first_code = code,
+	}
+
+	(first_code, out)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::VecDeque;
+	use std::io::{Read, Write};
+	use std::time::Duration;
+
+	use serialport::{ClearBuffer, DataBits, FlowControl, Parity, StopBits};
+
+	use super::*;
+
+	/// In-memory stand-in for a real port: `write` appends, `read` drains
+	/// from the front, so a `SerialTransfer` writing to one end and a
+	/// `PacketDecoder` fed from the same buffer exercise the exact bytes
+	/// that would cross a real wire. Every port-setting method is a no-op
+	/// stub; `SerialTransfer` never calls them.
+	struct LoopbackPort {
+		buffer : VecDeque<u8>,
+	}
+
+	impl LoopbackPort {
+		fn new() -> LoopbackPort {
+			LoopbackPort { buffer : VecDeque::new() }
+		}
+	}
+
+	impl Read for LoopbackPort {
+		fn read(&mut self, out : &mut [u8]) -> std::io::Result<usize> {
+			let mut n = 0;
+			while n < out.len() {
+				match self.buffer.pop_front() {
+					Some(byte) => { out[n] = byte; n += 1; },
+					None => break,
+				}
+			}
+			Ok(n)
+		}
+	}
+
+	impl Write for LoopbackPort {
+		fn write(&mut self, data : &[u8]) -> std::io::Result<usize> {
+			self.buffer.extend(data);
+			Ok(data.len())
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	impl SerialPort for LoopbackPort {
+		fn name(&self) -> Option<String> { None }
+		fn baud_rate(&self) -> serialport::Result<u32> { Ok(9600) }
+		fn data_bits(&self) -> serialport::Result<DataBits> { Ok(DataBits::Eight) }
+		fn flow_control(&self) -> serialport::Result<FlowControl> { Ok(FlowControl::None) }
+		fn parity(&self) -> serialport::Result<Parity> { Ok(Parity::None) }
+		fn stop_bits(&self) -> serialport::Result<StopBits> { Ok(StopBits::One) }
+		fn timeout(&self) -> Duration { Duration::from_secs(0) }
+		fn set_baud_rate(&mut self, _ : u32) -> serialport::Result<()> { Ok(()) }
+		fn set_data_bits(&mut self, _ : DataBits) -> serialport::Result<()> { Ok(()) }
+		fn set_flow_control(&mut self, _ : FlowControl) -> serialport::Result<()> { Ok(()) }
+		fn set_parity(&mut self, _ : Parity) -> serialport::Result<()> { Ok(()) }
+		fn set_stop_bits(&mut self, _ : StopBits) -> serialport::Result<()> { Ok(()) }
+		fn set_timeout(&mut self, _ : Duration) -> serialport::Result<()> { Ok(()) }
+		fn write_request_to_send(&mut self, _ : bool) -> serialport::Result<()> { Ok(()) }
+		fn write_data_terminal_ready(&mut self, _ : bool) -> serialport::Result<()> { Ok(()) }
+		fn read_clear_to_send(&mut self) -> serialport::Result<bool> { Ok(false) }
+		fn read_data_set_ready(&mut self) -> serialport::Result<bool> { Ok(false) }
+		fn read_ring_indicator(&mut self) -> serialport::Result<bool> { Ok(false) }
+		fn read_carrier_detect(&mut self) -> serialport::Result<bool> { Ok(false) }
+		fn bytes_to_read(&self) -> serialport::Result<u32> { Ok(self.buffer.len() as u32) }
+		fn bytes_to_write(&self) -> serialport::Result<u32> { Ok(0) }
+		fn clear(&self, _ : ClearBuffer) -> serialport::Result<()> { Ok(()) }
+		fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+			Err(Error::new(ErrorKind::Unknown, "LoopbackPort cannot be cloned"))
+		}
+		fn set_break(&self) -> serialport::Result<()> { Ok(()) }
+		fn clear_break(&self) -> serialport::Result<()> { Ok(()) }
+	}
+
+	fn loopback() -> SerialTransfer {
+		SerialTransfer::new(Box::new(LoopbackPort::new()))
+	}
+
+	#[test]
+	fn create_msg_round_trips_through_decoder() {
+		let mut transfer = loopback();
+		let packet = transfer.create_msg(0x1234u32).unwrap();
+
+		let mut decoder = PacketDecoder::new(CRC::new(0x9B));
+		let (state, consumed) = decoder.parse_read_bytes(&packet);
+
+		assert_eq!(state, ParseState::DataReady);
+		assert_eq!(consumed, packet.len());
+		assert_eq!(decoder.id_byte(), 0);
+		assert_eq!(u32::from_ne_bytes(decoder.payload().try_into().unwrap()), 0x1234);
+	}
+
+	#[test]
+	fn with_endianness_puts_a_single_scalar_on_the_wire_in_the_requested_order() {
+		let mut transfer = loopback().with_endianness(Endianness::Big);
+		let packet = transfer.create_msg(0x1234u32).unwrap();
+
+		let mut decoder = PacketDecoder::new(CRC::new(0x9B));
+		decoder.parse_read_bytes(&packet);
+
+		//the wire bytes themselves, not a round trip back through
+		//convert_endianness, which would hide a wrong intermediate format
+		assert_eq!(decoder.payload(), 0x1234u32.to_be_bytes());
+	}
+
+	#[test]
+	fn create_msg_round_trips_a_payload_with_the_start_byte_in_it() {
+		//a payload that needs real COBS stuffing, not just the empty/no-op case
+		let data : [u8; 7] = [1, 2, START_BYTE, 3, START_BYTE, START_BYTE, 4];
+
+		let mut transfer = loopback();
+		let packet = transfer.create_msg(data).unwrap();
+
+		let mut decoder = PacketDecoder::new(CRC::new(0x9B));
+		let (state, _) = decoder.parse_read_bytes(&packet);
+
+		assert_eq!(state, ParseState::DataReady);
+		assert_eq!(decoder.payload(), data.as_slice());
+	}
+
+	#[test]
+	fn send_and_available_round_trip_through_a_loopback_port() {
+		let mut transfer = loopback();
+		transfer.send(0xdeadbeefu32).unwrap();
+
+		let received : Option<u32> = transfer.available().unwrap();
+
+		assert_eq!(received, Some(0xdeadbeefu32));
+		assert_eq!(transfer.status(), TransferStatus::NewData);
+	}
+
+	#[test]
+	fn poll_dispatches_to_the_handler_registered_for_the_frame_id() {
+		let mut transfer = loopback();
+		transfer.send_with_id(5, 0x1234u32).unwrap();
+
+		let received = std::rc::Rc::new(std::cell::Cell::new(None));
+		let received_clone = received.clone();
+		transfer.register_handler(5, move |value : u32| received_clone.set(Some(value)));
+
+		transfer.poll().unwrap();
+
+		assert_eq!(received.get(), Some(0x1234));
+		assert_eq!(transfer.status(), TransferStatus::NewData);
+	}
+
+	#[test]
+	fn poll_reports_a_payload_error_when_the_handler_cant_decode_it() {
+		let mut transfer = loopback();
+		//a 1-byte payload can't possibly decode as a u32
+		transfer.send_with_id(5, 0xAAu8).unwrap();
+
+		transfer.register_handler(5, |_ : u32| panic!("should never decode"));
+		transfer.poll().unwrap();
+
+		assert_eq!(transfer.status(), TransferStatus::PayloadError);
+	}
+
+	#[test]
+	fn fragmented_send_reassembles_on_available() {
+		//payload bigger than the MTU, so send splits it across several frames
+		let data : [u8; 50] = std::array::from_fn(|i| i as u8);
+
+		let mut transfer = loopback().with_fragmentation(8);
+		transfer.send(data).unwrap();
+
+		let received : Option<[u8; 50]> = transfer.available().unwrap();
+		assert_eq!(received.unwrap().as_slice(), data.as_slice());
+	}
+
+	#[test]
+	fn encrypted_round_trip_authenticates_and_decodes() {
+		let key = [7u8; 32];
+
+		let mut transfer = loopback().with_encryption(key);
+		transfer.send(0x2222u32).unwrap();
+
+		let received : Option<u32> = transfer.available().unwrap();
+		assert_eq!(received, Some(0x2222));
+	}
+
+	#[test]
+	fn replayed_encrypted_frame_is_rejected() {
+		let key = [9u8; 32];
+		let mut transfer = loopback().with_encryption(key);
+		let packet = transfer.create_msg(0x3333u32).unwrap();
+
+		let cipher = ChaCha20Poly1305::new((&key).into());
+		let mut decoder = PacketDecoder::new(CRC::new(0x9B)).with_cipher(cipher);
+
+		let (first_state, _) = decoder.parse_read_bytes(&packet);
+		assert_eq!(first_state, ParseState::DataReady);
+
+		//the exact same frame again: same session prefix, same (non-increasing) counter
+		let (replay_state, _) = decoder.parse_read_bytes(&packet);
+		assert_eq!(replay_state, ParseState::AuthError);
+	}
+
+	#[test]
+	fn compressed_round_trip_decompresses_large_payloads() {
+		let data : [u8; 4096] = [0xABu8; 4096];
+
+		let mut transfer = loopback().with_compression(64);
+		transfer.send(data).unwrap();
+
+		let received : Option<[u8; 4096]> = transfer.available().unwrap();
+		assert_eq!(received.unwrap().as_slice(), data.as_slice());
+	}
+
+	#[test]
+	fn with_max_payload_size_bounds_inflate_even_without_fragmentation() {
+		//compression-only (no with_fragmentation): with_max_payload_size must
+		//still reach the decoder, or the zip-bomb guard silently stays at
+		//the 4 MiB default no matter what the caller asked for
+		let data : [u8; 4096] = [0xABu8; 4096];
+
+		let mut transfer = loopback().with_compression(8).with_max_payload_size(100);
+		transfer.send(data).unwrap();
+
+		let received : Option<[u8; 4096]> = transfer.available().unwrap();
+
+		assert_eq!(received, None);
+		assert_eq!(transfer.status(), TransferStatus::PayloadError);
+	}
+
+	#[test]
+	fn garbled_length_byte_is_a_payload_error_not_a_panic() {
+		let mut decoder = PacketDecoder::new(CRC::new(0x9B));
+		//start byte, id, overhead, then a length of 0 (never valid)
+		let (state, _) = decoder.parse_read_bytes(&[START_BYTE, 0, 1, 0]);
+		assert_eq!(state, ParseState::PayloadError);
+	}
+
+	#[test]
+	fn nonce_counter_exhaustion_is_a_hard_error() {
+		let mut transfer = loopback().with_encryption([1u8; 32]);
+		transfer.nonce_counter = Some(u32::MAX);
+
+		transfer.send(1u32).unwrap();
+		let result = transfer.send(2u32);
+
+		assert!(result.is_err());
+	}
+}
+
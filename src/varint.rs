@@ -0,0 +1,74 @@
+/// Longest a LEB128-encoded `usize` can legally run: enough 7-bit groups to
+/// cover every bit of the target's pointer width. A continuation run past
+/// this is garbled or malicious, never a real length/fragment field.
+const MAX_BYTES : u32 = usize::BITS.div_ceil(7);
+
+/// Outcome of feeding one byte into a [`VarintReader`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VarintOutcome {
+	/// More continuation bytes are expected.
+	Pending,
+	/// The varint terminated; here's the decoded value.
+	Done(usize),
+	/// `MAX_BYTES` bytes arrived, every one with the continuation bit set,
+	/// without the varint terminating.
+	Overflow,
+}
+
+/// Incremental unsigned LEB128 varint reader: feed bytes one at a time,
+/// low 7 bits first, with the continuation bit (`0x80`) set while more
+/// bytes follow. Used to decode frame fields byte-by-byte inside
+/// `PacketDecoder`'s state machine, where a whole slice isn't available
+/// up front.
+#[derive(Debug, Default)]
+pub struct VarintReader {
+	value : usize,
+	shift : u32,
+	bytes_read : u32,
+}
+
+impl VarintReader {
+	/// Feeds one byte in. Returns [`VarintOutcome::Done`] once the varint
+	/// terminates (a byte with the continuation bit clear) and resets
+	/// internal state so the reader is ready for the next varint;
+	/// [`VarintOutcome::Overflow`] if it runs past `MAX_BYTES` without
+	/// terminating, also resetting so the caller can recover.
+	pub fn push(&mut self, byte : u8) -> VarintOutcome {
+		self.value |= ((byte & 0x7F) as usize) << self.shift;
+		self.shift += 7;
+		self.bytes_read += 1;
+
+		if byte & 0x80 == 0 {
+			let value = self.value;
+			*self = Self::default();
+			VarintOutcome::Done(value)
+		} else if self.bytes_read == MAX_BYTES {
+			*self = Self::default();
+			VarintOutcome::Overflow
+		} else {
+			VarintOutcome::Pending
+		}
+	}
+}
+
+/// Encodes `value` as an unsigned LEB128 varint.
+pub fn encode(mut value : usize) -> Vec<u8> {
+	let mut out = Vec::new();
+
+	loop {
+		let mut byte = (value & 0x7F) as u8;
+		value >>= 7;
+
+		if value != 0 {
+			byte |= 0x80;
+		}
+
+		out.push(byte);
+
+		if value == 0 {
+			break;
+		}
+	}
+
+	out
+}